@@ -0,0 +1,101 @@
+use server_backend::method::Method;
+
+// Which request origins an endpoint's CORS configuration allows.
+pub enum OriginPolicy {
+    AnyOrigin,
+    Origins(Vec<String>),
+}
+
+pub struct CorsOptions {
+    pub origins: OriginPolicy,
+    pub methods: Vec<Method>,
+    pub headers: Vec<String>,
+}
+
+pub type CorsBuildHandler = |&mut CorsOptions|: 'static;
+
+impl CorsOptions {
+
+    pub fn new() -> CorsOptions {
+        CorsOptions {
+            origins: OriginPolicy::AnyOrigin,
+            methods: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn origins(&mut self, origins: Vec<String>) {
+        self.origins = OriginPolicy::Origins(origins);
+    }
+
+    pub fn any_origin(&mut self) {
+        self.origins = OriginPolicy::AnyOrigin;
+    }
+
+    pub fn methods(&mut self, methods: Vec<Method>) {
+        self.methods = methods;
+    }
+
+    pub fn headers(&mut self, headers: Vec<String>) {
+        self.headers = headers;
+    }
+
+    // The `Access-Control-Allow-Origin` value to send back for a request's
+    // `Origin` header, or `None` if that origin isn't allowed at all.
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        match self.origins {
+            OriginPolicy::AnyOrigin => Some("*".to_string()),
+            OriginPolicy::Origins(ref allowed) => {
+                match allowed.iter().find(|candidate| candidate.as_slice() == origin) {
+                    Some(_) => Some(origin.to_string()),
+                    None => None
+                }
+            }
+        }
+    }
+
+    pub fn allow_methods(&self) -> String {
+        self.methods.iter().map(|method| method.to_string()).collect::<Vec<String>>().connect(", ")
+    }
+
+    pub fn allow_headers(&self) -> String {
+        self.headers.connect(", ")
+    }
+}
+
+// Checks every header name in a comma-separated
+// `Access-Control-Request-Headers` value against the endpoint's configured
+// allowed headers, case-insensitively.
+pub fn headers_allowed(requested: &str, allowed: &[String]) -> bool {
+    requested.split(',').all(|header| {
+        let header = header.trim();
+        allowed.iter().any(|candidate| candidate.as_slice().eq_ignore_ascii_case(header))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CorsOptions, headers_allowed};
+
+    #[test]
+    fn any_origin_allows_everything() {
+        let options = CorsOptions::new();
+        assert_eq!(options.allow_origin("http://example.com"), Some("*".to_string()));
+    }
+
+    #[test]
+    fn origins_list_only_allows_listed_origins() {
+        let mut options = CorsOptions::new();
+        options.origins(vec!["http://example.com".to_string()]);
+
+        assert_eq!(options.allow_origin("http://example.com"), Some("http://example.com".to_string()));
+        assert_eq!(options.allow_origin("http://evil.com"), None);
+    }
+
+    #[test]
+    fn headers_allowed_is_case_insensitive() {
+        let allowed = vec!["X-Api-Key".to_string(), "Content-Type".to_string()];
+        assert!(headers_allowed("x-api-key, content-type", allowed.as_slice()));
+        assert!(!headers_allowed("x-api-key, x-unknown", allowed.as_slice()));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::TreeMap;
 use serialize::json;
 use serialize::json::{Json, JsonObject};
 use serialize::json::ToJson;
@@ -9,7 +10,12 @@ use server_backend::method::{Method};
 use server::{Request, Response};
 use middleware::{HandleResult, NotMatchError, Error};
 use framework::path::{Path};
-use framework::errors::{QueryStringDecodeError, ValidationError, BodyDecodeError};
+use framework::errors::{QueryStringDecodeError, ValidationError, BodyDecodeError, NotAcceptableError};
+use framework::multipart;
+use framework::negotiation;
+use framework::negotiation::Serializer;
+use framework::cors;
+use framework::cors::CorsBuildHandler;
 use framework::{
     ApiHandler, ValicoBuildHandler, Client, CallInfo
 };
@@ -29,6 +35,17 @@ pub struct Endpoint {
     pub desc: Option<String>,
     pub coercer: Option<ValicoBuilder>,
     handler: Option<EndpointHandler>,
+    // raw path spec as passed to `new`, kept around so it can be translated
+    // into an OpenAPI-style templated path (`:id` -> `{id}`) without having
+    // to teach `Path` how to render itself back out.
+    spec: String,
+    // MIME type -> serializer, in the order they were registered with
+    // `produces`. Empty by default; `negotiate` appends the built-in
+    // `application/json` fallback *after* these, so a `produces` call that
+    // overrides `application/json` is tried before the fallback ever is.
+    serializers: Vec<(String, Serializer)>,
+    // CORS configuration, if this endpoint should be callable cross-origin.
+    cors: Option<cors::CorsOptions>,
 }
 
 impl Endpoint {
@@ -39,7 +56,10 @@ impl Endpoint {
             path: Path::parse(path, true).unwrap(),
             desc: None,
             coercer: None,
-            handler: None
+            handler: None,
+            spec: path.to_string(),
+            serializers: Vec::new(),
+            cors: None,
         }
     }
 
@@ -58,11 +78,160 @@ impl Endpoint {
         self.coercer = Some(ValicoBuilder::build(builder));
     }
 
+    // Registers a serializer for a MIME type this endpoint can produce, for
+    // use by response content negotiation. The client's `Accept` order
+    // decides first; when several registered serializers match the same
+    // `Accept` entry (e.g. `*/*`), the one registered first is used.
+    pub fn produces(&mut self, mime: &str, serializer: Serializer) {
+        self.serializers.push((mime.to_string(), serializer));
+    }
+
+    // Enables CORS for this endpoint: an `Origin` header on a normal
+    // request gets matching `Access-Control-Allow-*` response headers, and
+    // a matching `OPTIONS` preflight is answered directly in `api_call`
+    // without reaching the handler.
+    pub fn cors(&mut self, builder: CorsBuildHandler) {
+        let mut options = cors::CorsOptions::new();
+        builder(&mut options);
+        self.cors = Some(options);
+    }
+
     pub fn handle(&mut self, handler: EndpointHandler) -> EndpointHandlerPresent {
         self.handler = Some(handler);
         HandlerPresent
     }
 
+    // Translates a rustless path spec (`users/:id/posts`) into the templated
+    // path OpenAPI expects (`users/{id}/posts`).
+    fn openapi_path(&self) -> String {
+        self.spec.as_slice().split('/').map(|segment| {
+            if segment.starts_with(":") {
+                format!("{{{}}}", segment.slice_from(1))
+            } else {
+                segment.to_string()
+            }
+        }).collect::<Vec<String>>().connect("/")
+    }
+
+    // The `:name` capture segments of `self.spec`, used to tell which
+    // valico params are path captures rather than query/body params.
+    fn path_capture_names(&self) -> Vec<String> {
+        self.spec.as_slice().split('/').filter_map(|segment| {
+            if segment.starts_with(":") {
+                Some(segment.slice_from(1).to_string())
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    // Methods whose params are conventionally sent as a JSON body rather
+    // than the query string.
+    fn has_request_body(&self) -> bool {
+        match self.method {
+            Method::Post | Method::Put | Method::Patch => true,
+            _ => false
+        }
+    }
+
+    // Splits the valico coercer's params into the OpenAPI `parameters`
+    // array (path captures, always `required`, plus query params for
+    // methods without a body) and a `requestBody` schema (everything else,
+    // for POST/PUT/PATCH).
+    fn openapi_parameters_and_body(&self) -> (Vec<json::Json>, Option<json::Json>) {
+        let coercer = match self.coercer {
+            Some(ref coercer) => coercer,
+            None => return (Vec::new(), None)
+        };
+
+        let captures = self.path_capture_names();
+        let has_request_body = self.has_request_body();
+
+        let mut parameters = Vec::new();
+        let mut body_properties: JsonObject = TreeMap::new();
+        let mut body_required = Vec::new();
+
+        for param in coercer.params.iter() {
+            if captures.contains(&param.name) {
+                let mut obj: JsonObject = TreeMap::new();
+                obj.insert("name".to_string(), param.name.to_json());
+                obj.insert("in".to_string(), "path".to_json());
+                obj.insert("required".to_string(), true.to_json());
+                obj.insert("schema".to_string(), param.coercer.to_json());
+                parameters.push(json::Object(obj));
+            } else if has_request_body {
+                body_properties.insert(param.name.clone(), param.coercer.to_json());
+                if param.required {
+                    body_required.push(param.name.to_json());
+                }
+            } else {
+                let mut obj: JsonObject = TreeMap::new();
+                obj.insert("name".to_string(), param.name.to_json());
+                obj.insert("in".to_string(), "query".to_json());
+                obj.insert("required".to_string(), param.required.to_json());
+                obj.insert("schema".to_string(), param.coercer.to_json());
+                parameters.push(json::Object(obj));
+            }
+        }
+
+        let request_body = if body_properties.is_empty() {
+            None
+        } else {
+            let mut schema: JsonObject = TreeMap::new();
+            schema.insert("type".to_string(), "object".to_json());
+            schema.insert("properties".to_string(), json::Object(body_properties));
+            if !body_required.is_empty() {
+                schema.insert("required".to_string(), body_required.to_json());
+            }
+
+            let mut media_type: JsonObject = TreeMap::new();
+            media_type.insert("schema".to_string(), json::Object(schema));
+
+            let mut content: JsonObject = TreeMap::new();
+            content.insert("application/json".to_string(), json::Object(media_type));
+
+            let mut request_body: JsonObject = TreeMap::new();
+            request_body.insert("content".to_string(), json::Object(content));
+            Some(json::Object(request_body))
+        };
+
+        (parameters, request_body)
+    }
+
+    // Produces the OpenAPI "operation object" for this single endpoint:
+    // summary/description from `desc` and parameters coerced from valico.
+    // Callers (typically the owning `Api`/`Namespace`) merge this into the
+    // shared `paths` map of a full OpenAPI document, keyed by `openapi_path`
+    // and HTTP method.
+    pub fn describe(&self) -> (String, String, json::Json) {
+        let mut operation: JsonObject = TreeMap::new();
+
+        match self.desc {
+            Some(ref desc) => {
+                operation.insert("summary".to_string(), desc.to_json());
+                operation.insert("description".to_string(), desc.to_json());
+            },
+            None => ()
+        }
+
+        let (parameters, request_body) = self.openapi_parameters_and_body();
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), parameters.to_json());
+        }
+        match request_body {
+            Some(body) => { operation.insert("requestBody".to_string(), body); },
+            None => ()
+        }
+
+        let mut responses: JsonObject = TreeMap::new();
+        let mut default_response: JsonObject = TreeMap::new();
+        default_response.insert("description".to_string(), "default response".to_json());
+        responses.insert("default".to_string(), json::Object(default_response));
+        operation.insert("responses".to_string(), json::Object(responses));
+
+        (self.openapi_path(), self.method.to_string().to_ascii_lower(), json::Object(operation))
+    }
+
     fn validate(&self, params: &mut JsonObject) -> HandleResult<()> {
         // Validate namespace params with valico
         if self.coercer.is_some() {
@@ -85,6 +254,8 @@ impl Endpoint {
             try!((*cb)(&mut client));
         }
 
+        let mut uploaded_files = None;
+
         {
 
         let req: &mut Request = client.request;
@@ -109,7 +280,7 @@ impl Endpoint {
         // extend params with json-encoded body params if any
         if req.is_json_body() {
             let maybe_body = req.read_to_end();
-        
+
             let utf8_string_body = {
                 match maybe_body {
                     Ok(body) => {
@@ -133,11 +304,63 @@ impl Endpoint {
                         }
                     },
                     Err(err) => return Err(BodyDecodeError::new(format!("{}", err)).abstract())
-                }  
+                }
+            }
+        // extend params with form-urlencoded body params if any
+        } else if req.is_form_urlencoded_body() {
+            let body = match req.read_to_end() {
+                Ok(body) => body,
+                Err(err) => return Err(BodyDecodeError::new(format!("{}", err)).abstract())
+            };
+
+            if body.len() > 0 {
+                let utf8_string_body = match String::from_utf8(body) {
+                    Ok(e) => e,
+                    Err(_) => return Err(BodyDecodeError::new("Invalid UTF-8 sequence".to_string()).abstract()),
+                };
+
+                match query::parse(utf8_string_body.as_slice()) {
+                    Ok(form_params) => {
+                        for (key, value) in form_params.as_object().unwrap().iter() {
+                            if !params.contains_key(key) {
+                                params.insert(key.to_string(), value.clone());
+                            }
+                        }
+                    },
+                    Err(_) => return Err(QueryStringDecodeError.abstract())
+                }
+            }
+        // extend params (and the client's uploaded files) from a
+        // multipart/form-data body if any
+        } else if req.is_multipart_body() {
+            let boundary = match req.content_type().as_ref().and_then(|ct| multipart::parse_boundary(ct.as_slice())) {
+                Some(boundary) => boundary,
+                None => return Err(BodyDecodeError::new("Missing multipart boundary".to_string()).abstract())
+            };
+
+            let body = match req.read_to_end() {
+                Ok(body) => body,
+                Err(err) => return Err(BodyDecodeError::new(format!("{}", err)).abstract())
+            };
+
+            match multipart::parse(body.as_slice(), boundary.as_slice()) {
+                Ok((form_params, files)) => {
+                    for (key, value) in form_params.iter() {
+                        if !params.contains_key(key) {
+                            params.insert(key.to_string(), value.clone());
+                        }
+                    }
+                    uploaded_files = Some(files);
+                },
+                Err(err) => return Err(BodyDecodeError::new(err.reason).abstract())
             }
         }
 
-        }   
+        }
+
+        if uploaded_files.is_some() {
+            client.set_uploaded_files(uploaded_files.unwrap());
+        }
 
         for cb in info.before_validation.iter() {
             try!((*cb)(&mut client));
@@ -152,14 +375,117 @@ impl Endpoint {
         let ref handler = self.handler.unwrap();
         // fixme not efficient to_json call
         let mut client = try!((*handler)(client, &params.to_json()));
-            
+
         for cb in info.after.iter() {
             try!((*cb)(&mut client));
         }
 
-        Ok(client.move_response())
+        self.negotiate(&mut client, params, info)
+    }
+
+    // Picks the best serializer for the response, preferring an explicit
+    // `format` param (e.g. `?format=json`) over the `Accept` header, and
+    // falls back to `application/json` when the client sent neither. Per-
+    // endpoint serializers registered via `produces` take priority over
+    // ones registered API-wide on `CallInfo`, which in turn take priority
+    // over the built-in `application/json` fallback appended last - so a
+    // `produces("application/json", ...)` call can actually override it.
+    fn negotiate(&self, client: &mut Client, params: &JsonObject, info: &CallInfo) -> HandleResult<Response> {
+        let wanted = match params.find(&"format".to_string()).and_then(|format| format.as_string()) {
+            Some(format) => match negotiation::mime_for_format(format) {
+                Some(mime) => vec![mime],
+                None => vec![format.to_string()]
+            },
+            None => match client.request.accept() {
+                Some(accept_header) => negotiation::parse_accept(accept_header.as_slice()),
+                None => vec!["application/json".to_string()]
+            }
+        };
+
+        let mut available = self.serializers.clone();
+        available.push_all(info.serializers.as_slice());
+        available.push(("application/json".to_string(), negotiation::json_serializer));
+
+        match negotiation::negotiate(wanted.as_slice(), available.as_slice()) {
+            Some(&(ref mime, serializer)) => {
+                let mut response = client.move_response();
+                let bytes = serializer(response.body_json());
+                response.set_content_type(mime.clone());
+                response.set_body(bytes);
+                Ok(response)
+            },
+            None => Err(NotAcceptableError.abstract())
+        }
+    }
+
+}
+
+impl Endpoint {
+
+    // Answers a CORS preflight request for this endpoint without reaching
+    // the handler: an `OPTIONS` request with an `Origin` that matches
+    // `self.cors` gets back the allowed methods/headers for this endpoint,
+    // validating `Access-Control-Request-Headers` case-insensitively.
+    fn preflight(&self, req: &Request) -> HandleResult<Response> {
+        let options = self.cors.as_ref().unwrap();
+
+        let origin = match req.header("origin") {
+            Some(origin) => origin,
+            None => return Err(NotMatchError.abstract())
+        };
+
+        let allow_origin = match options.allow_origin(origin.as_slice()) {
+            Some(allow_origin) => allow_origin,
+            None => return Err(NotMatchError.abstract())
+        };
+
+        match req.header("access-control-request-headers") {
+            Some(requested_headers) => {
+                if !cors::headers_allowed(requested_headers.as_slice(), options.headers.as_slice()) {
+                    return Err(NotMatchError.abstract());
+                }
+            },
+            None => ()
+        }
+
+        // Fall back to this endpoint's own method when no explicit
+        // `methods` list was configured (e.g. `endpoint.cors(|_| {})`),
+        // otherwise the most common case - a preflight ahead of a real
+        // request this endpoint actually handles - gets back an empty
+        // `Access-Control-Allow-Methods` and the browser blocks it anyway.
+        let allow_methods = if options.methods.is_empty() {
+            self.method.to_string()
+        } else {
+            options.allow_methods()
+        };
+
+        let mut response = Response::new();
+        response.set_header("Access-Control-Allow-Origin".to_string(), allow_origin);
+        response.set_header("Access-Control-Allow-Methods".to_string(), allow_methods);
+        response.set_header("Access-Control-Allow-Headers".to_string(), options.allow_headers());
+
+        Ok(response)
+    }
+
+    // The `Access-Control-Allow-Origin` value for a request's `Origin`
+    // header, if this endpoint has CORS enabled and that origin is
+    // allowed. Takes the header value directly (rather than `&Request`)
+    // so this can be tested without a full `Request`.
+    fn cors_allow_origin_for(&self, origin: Option<&str>) -> Option<String> {
+        match self.cors {
+            Some(ref options) => {
+                match origin {
+                    Some(origin) => options.allow_origin(origin),
+                    None => None
+                }
+            },
+            None => None
+        }
     }
 
+    fn cors_allow_origin(&self, req: &Request) -> Option<String> {
+        self.cors_allow_origin_for(req.header("origin").as_ref().map(|origin| origin.as_slice()))
+    }
 }
 
 impl ApiHandler for Endpoint {
@@ -168,10 +494,100 @@ impl ApiHandler for Endpoint {
         match self.path.is_match(rest_path) {
             Some(captures) =>  {
                 self.path.apply_captures(params, captures);
+
+                if self.cors.is_some() && req.method == Method::Options {
+                    return self.preflight(req);
+                }
+
+                // Record the CORS header on `info.response_headers` - the
+                // single place it's applied from - rather than also
+                // setting it directly on a successful `Response`.
+                // `info.response_headers` is merged onto whatever response
+                // the dispatcher ends up building, for both `Ok` and `Err`
+                // (call_decode can fail at any point: validation, body
+                // decoding, content negotiation), so a routine validation
+                // failure still reaches the browser instead of looking
+                // like a blocked CORS request. Setting it a second time
+                // directly on the `Response` here as well would send a
+                // duplicate `Access-Control-Allow-Origin`, which makes
+                // browsers reject the response outright.
+                match self.cors_allow_origin(req) {
+                    Some(allow_origin) => {
+                        info.response_headers.push(("Access-Control-Allow-Origin".to_string(), allow_origin));
+                    },
+                    None => ()
+                }
+
                 self.call_decode(params, req, info)
             },
             None => Err(NotMatchError.abstract())
         }
 
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use server_backend::method::Method;
+    use super::Endpoint;
+
+    #[test]
+    fn openapi_path_templates_captures() {
+        let endpoint = Endpoint::new(Method::Get, "users/:id/posts/:post_id");
+        assert_eq!(endpoint.openapi_path(), "users/{id}/posts/{post_id}".to_string());
+    }
+
+    #[test]
+    fn openapi_path_leaves_static_segments_alone() {
+        let endpoint = Endpoint::new(Method::Get, "users");
+        assert_eq!(endpoint.openapi_path(), "users".to_string());
+    }
+
+    #[test]
+    fn path_capture_names_finds_every_capture() {
+        let endpoint = Endpoint::new(Method::Get, "users/:id/posts/:post_id");
+        assert_eq!(endpoint.path_capture_names(), vec!["id".to_string(), "post_id".to_string()]);
+    }
+
+    #[test]
+    fn path_capture_names_empty_for_a_static_path() {
+        let endpoint = Endpoint::new(Method::Get, "users");
+        assert!(endpoint.path_capture_names().is_empty());
+    }
+
+    #[test]
+    fn has_request_body_only_for_body_bearing_methods() {
+        assert!(Endpoint::new(Method::Post, "users").has_request_body());
+        assert!(Endpoint::new(Method::Put, "users/:id").has_request_body());
+        assert!(Endpoint::new(Method::Patch, "users/:id").has_request_body());
+        assert!(!Endpoint::new(Method::Get, "users").has_request_body());
+        assert!(!Endpoint::new(Method::Delete, "users/:id").has_request_body());
+    }
+
+    #[test]
+    fn cors_allow_origin_for_is_none_without_cors_enabled() {
+        let endpoint = Endpoint::new(Method::Get, "users");
+        assert_eq!(endpoint.cors_allow_origin_for(Some("http://example.com")), None);
+    }
+
+    #[test]
+    fn cors_allow_origin_for_is_none_without_an_origin_header() {
+        let mut endpoint = Endpoint::new(Method::Get, "users");
+        endpoint.cors(|_| {});
+        assert_eq!(endpoint.cors_allow_origin_for(None), None);
+    }
+
+    #[test]
+    fn cors_allow_origin_for_matches_only_allowed_origins() {
+        let mut endpoint = Endpoint::new(Method::Get, "users");
+        endpoint.cors(|options| {
+            options.origins(vec!["http://example.com".to_string()]);
+        });
+
+        assert_eq!(
+            endpoint.cors_allow_origin_for(Some("http://example.com")),
+            Some("http://example.com".to_string())
+        );
+        assert_eq!(endpoint.cors_allow_origin_for(Some("http://evil.com")), None);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,273 @@
+use std::str;
+use std::io::TempDir;
+use serialize::json::{JsonObject, ToJson};
+
+// A single `multipart/form-data` part that carried a `filename` in its
+// `Content-Disposition` header, i.e. an uploaded file rather than a plain
+// scalar field. Kept distinct from `params` so the valico coercer only ever
+// has to validate the scalar `JsonObject`.
+pub struct UploadedFile {
+    pub name: String,
+    pub filename: String,
+    pub content_type: String,
+    pub data: FileData,
+}
+
+// Small uploads are kept in memory; anything past `SPILL_THRESHOLD` bytes is
+// written out to a temp file so a handful of large uploads can't blow up
+// process memory. The `TempDir` is held alongside the path (rather than
+// unwrapped into a bare `Path`) so the spilled file is removed automatically
+// once the `UploadedFile` carrying it is dropped, instead of leaking for the
+// life of the process.
+pub enum FileData {
+    Memory(Vec<u8>),
+    Spilled(TempDir, Path),
+}
+
+impl FileData {
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            FileData::Spilled(_, ref path) => Some(path),
+            FileData::Memory(_) => None
+        }
+    }
+}
+
+static SPILL_THRESHOLD: uint = 256 * 1024;
+
+#[deriving(Show)]
+pub struct MultipartError {
+    pub reason: String,
+}
+
+// Pulls the `boundary=...` parameter out of a `multipart/form-data`
+// Content-Type header value.
+pub fn parse_boundary(content_type: &str) -> Option<String> {
+    for part in content_type.split(';') {
+        let trimmed = part.trim();
+        if trimmed.starts_with("boundary=") {
+            let value = trimmed.slice_from("boundary=".len());
+            let unquoted = value.trim_matches('"');
+            return Some(unquoted.to_string());
+        }
+    }
+    None
+}
+
+// Splits a multipart body on `--boundary` delimiters, and for each part
+// either inserts its text value into `params` or, if it declared a
+// `filename`, returns it as an `UploadedFile`.
+//
+// This works on the raw bytes rather than decoding the whole body as UTF-8
+// up front: boundaries and headers are ASCII, but a part's content is
+// arbitrary binary data (images, PDFs, zips, ...) that's very unlikely to
+// be valid UTF-8, so only each part's header block ever gets decoded to a
+// `str`.
+pub fn parse(body: &[u8], boundary: &str) -> Result<(JsonObject, Vec<UploadedFile>), MultipartError> {
+    let delimiter = format!("--{}", boundary);
+
+    let mut params = JsonObject::new();
+    let mut files = Vec::new();
+
+    for raw_part in split_bytes(body, delimiter.as_bytes()).into_iter() {
+        let part = trim_crlf(raw_part);
+        if part.len() == 0 || part == b"--" {
+            continue;
+        }
+
+        let header_end = match find_bytes(part, b"\r\n\r\n") {
+            Some(idx) => idx,
+            None => continue
+        };
+
+        let headers = match str::from_utf8(part.slice_to(header_end)) {
+            Some(headers) => headers,
+            None => return Err(MultipartError { reason: "multipart part headers are not valid UTF-8".to_string() })
+        };
+        let content = trim_crlf(part.slice_from(header_end + 4));
+
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = "text/plain".to_string();
+
+        for header_line in headers.split_str("\r\n") {
+            let lower = header_line.to_ascii_lower();
+            if lower.starts_with("content-disposition:") {
+                for piece in header_line.split(';').skip(1) {
+                    let piece = piece.trim();
+                    if piece.starts_with("name=") {
+                        name = Some(piece.slice_from("name=".len()).trim_matches('"').to_string());
+                    } else if piece.starts_with("filename=") {
+                        filename = Some(piece.slice_from("filename=".len()).trim_matches('"').to_string());
+                    }
+                }
+            } else if lower.starts_with("content-type:") {
+                content_type = header_line.slice_from("content-type:".len()).trim().to_string();
+            }
+        }
+
+        let name = match name {
+            Some(name) => name,
+            None => continue
+        };
+
+        match filename {
+            Some(filename) => {
+                let bytes = content.to_vec();
+                let data = if bytes.len() > SPILL_THRESHOLD {
+                    match spill_to_temp_file(bytes.as_slice()) {
+                        Ok((dir, path)) => FileData::Spilled(dir, path),
+                        Err(err) => return Err(MultipartError { reason: format!("{}", err) })
+                    }
+                } else {
+                    FileData::Memory(bytes)
+                };
+
+                files.push(UploadedFile {
+                    name: name,
+                    filename: filename,
+                    content_type: content_type,
+                    data: data,
+                });
+            },
+            None => {
+                let value = match str::from_utf8(content) {
+                    Some(value) => value,
+                    None => return Err(MultipartError { reason: "multipart field is not valid UTF-8".to_string() })
+                };
+                params.insert(name, value.to_string().to_json());
+            }
+        }
+    }
+
+    Ok((params, files))
+}
+
+// The first index of `needle` in `haystack`, or `None` if it isn't present.
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    if needle.len() == 0 || haystack.len() < needle.len() {
+        return None;
+    }
+
+    for i in range(0u, haystack.len() - needle.len() + 1) {
+        if haystack.slice(i, i + needle.len()) == needle {
+            return Some(i);
+        }
+    }
+
+    None
+}
+
+// Splits `haystack` on every occurrence of `needle`, byte-for-byte (the
+// `&[u8]` equivalent of `str::split_str`).
+fn split_bytes<'a>(haystack: &'a [u8], needle: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = haystack;
+
+    loop {
+        match find_bytes(rest, needle) {
+            Some(idx) => {
+                parts.push(rest.slice_to(idx));
+                rest = rest.slice_from(idx + needle.len());
+            },
+            None => {
+                parts.push(rest);
+                break;
+            }
+        }
+    }
+
+    parts
+}
+
+// Trims leading/trailing `\r` and `\n` bytes, the `&[u8]` equivalent of
+// `str::trim_chars(|c: char| c == '\r' || c == '\n')`.
+fn trim_crlf(bytes: &[u8]) -> &[u8] {
+    fn is_crlf(b: &u8) -> bool { *b == b'\r' || *b == b'\n' }
+
+    let start = bytes.iter().position(|b| !is_crlf(b)).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !is_crlf(b)).map(|i| i + 1).unwrap_or(0);
+
+    if start >= end {
+        bytes.slice_to(0)
+    } else {
+        bytes.slice(start, end)
+    }
+}
+
+fn spill_to_temp_file(bytes: &[u8]) -> ::std::io::IoResult<(TempDir, Path)> {
+    use std::io::File;
+
+    let dir = try!(TempDir::new("rustless-upload"));
+    let path = dir.path().join("upload");
+    let mut file = try!(File::create(&path));
+    try!(file.write(bytes));
+    Ok((dir, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_boundary, FileData};
+
+    #[test]
+    fn parses_boundary_from_content_type() {
+        let content_type = "multipart/form-data; boundary=----WebKitBoundary42";
+        assert_eq!(parse_boundary(content_type), Some("----WebKitBoundary42".to_string()));
+    }
+
+    #[test]
+    fn parses_scalar_field_and_uploaded_file() {
+        let boundary = "----Boundary42";
+        let body = format!(
+            "--{boundary}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             hello world\r\n\
+             --{boundary}\r\n\
+             Content-Disposition: form-data; name=\"avatar\"; filename=\"me.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             file contents\r\n\
+             --{boundary}--\r\n",
+            boundary = boundary
+        );
+
+        let (params, files) = parse(body.as_bytes(), boundary).unwrap();
+
+        assert_eq!(params.find(&"title".to_string()).unwrap().as_string(), Some("hello world"));
+
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.name.as_slice(), "avatar");
+        assert_eq!(file.filename.as_slice(), "me.txt");
+        assert_eq!(file.content_type.as_slice(), "text/plain");
+        match file.data {
+            FileData::Memory(ref bytes) => assert_eq!(bytes.as_slice(), b"file contents"),
+            FileData::Spilled(..) => panic!("expected an in-memory file for a small upload")
+        }
+    }
+
+    #[test]
+    fn parses_binary_file_content_that_is_not_utf8() {
+        let boundary = "----Boundary42";
+        let mut body = Vec::new();
+        body.push_all(format!(
+            "--{boundary}\r\n\
+             Content-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\n\
+             Content-Type: image/png\r\n\r\n",
+            boundary = boundary
+        ).as_bytes());
+        // Bytes that are not valid UTF-8 (continuation bytes with no
+        // leading byte, and 0xff/0xfe, which never occur in UTF-8),
+        // standing in for real binary file content such as a PNG.
+        let binary_content: Vec<u8> = vec![0x89, 0x50, 0x4e, 0x47, 0xff, 0xfe, 0x00, 0x80];
+        body.push_all(binary_content.as_slice());
+        body.push_all(format!("\r\n--{boundary}--\r\n", boundary = boundary).as_bytes());
+
+        let (_, files) = parse(body.as_slice(), boundary).unwrap();
+
+        assert_eq!(files.len(), 1);
+        match files[0].data {
+            FileData::Memory(ref bytes) => assert_eq!(bytes.as_slice(), binary_content.as_slice()),
+            FileData::Spilled(..) => panic!("expected an in-memory file for a small upload")
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+use serialize::json::Json;
+
+// Serializes a response body into bytes for one negotiated MIME type.
+pub type Serializer = fn(&Json) -> Vec<u8>;
+
+// Default serializer used when nothing more specific is registered; keeps
+// existing endpoints (which never call `produces`) working exactly as
+// before content negotiation was added.
+pub fn json_serializer(body: &Json) -> Vec<u8> {
+    body.to_string().into_bytes()
+}
+
+// Maps a `format` query/body param (e.g. `?format=json`) to the MIME type
+// it stands for, so callers can skip `Accept` header parsing entirely.
+pub fn mime_for_format(format: &str) -> Option<String> {
+    match format {
+        "json" => Some("application/json".to_string()),
+        _ => None
+    }
+}
+
+// A single entry of a parsed `Accept` header, e.g. `application/json;q=0.8`.
+struct AcceptEntry {
+    mime: String,
+    q: f32,
+}
+
+// Parses an `Accept` header into MIME types ordered from most to least
+// preferred, mirroring how browsers send an ordered, q-weighted list.
+pub fn parse_accept(header: &str) -> Vec<String> {
+    let mut entries: Vec<AcceptEntry> = header.split(',').filter_map(|raw| {
+        let raw = raw.trim();
+        if raw.len() == 0 {
+            return None;
+        }
+
+        let mut parts = raw.split(';');
+        let mime = parts.next().unwrap().trim().to_string();
+        let mut q = 1.0f32;
+
+        for param in parts {
+            let param = param.trim();
+            if param.starts_with("q=") {
+                // A client can send a garbage or non-finite `q` (e.g.
+                // `q=NaN`); treat anything we can't use as a comparable
+                // weight as the default rather than trusting it to sort.
+                q = match from_str::<f32>(param.slice_from(2)) {
+                    Some(parsed) if parsed.is_finite() => parsed,
+                    _ => 1.0f32
+                };
+            }
+        }
+
+        Some(AcceptEntry { mime: mime, q: q })
+    }).collect();
+
+    // stable sort so entries with an equal q-value keep the order the
+    // client sent them in
+    entries.sort_by(|a, b| b.q.partial_cmp(&a.q).unwrap_or(Ordering::Equal));
+
+    entries.into_iter().map(|entry| entry.mime).collect()
+}
+
+// Picks the best serializer for an ordered list of acceptable MIME types,
+// supporting wildcards (`application/*`, `*/*`) on the client side.
+pub fn negotiate<'a>(accept: &[String], available: &'a [(String, Serializer)]) -> Option<&'a (String, Serializer)> {
+    for wanted in accept.iter() {
+        for candidate in available.iter() {
+            if mime_matches(wanted.as_slice(), candidate.0.as_slice()) {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+fn mime_matches(wanted: &str, candidate: &str) -> bool {
+    if wanted == "*/*" || wanted == candidate {
+        return true;
+    }
+
+    let mut wanted_parts = wanted.splitn(2, '/');
+    let mut candidate_parts = candidate.splitn(2, '/');
+    let wanted_type = wanted_parts.next().unwrap_or("");
+    let wanted_subtype = wanted_parts.next().unwrap_or("");
+    let candidate_type = candidate_parts.next().unwrap_or("");
+    let candidate_subtype = candidate_parts.next().unwrap_or("");
+
+    wanted_type == candidate_type && (wanted_subtype == "*" || wanted_subtype == candidate_subtype)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_accept, negotiate, json_serializer};
+
+    #[test]
+    fn parse_accept_orders_by_quality() {
+        let mimes = parse_accept("text/html;q=0.8, application/json, application/xml;q=0.9");
+        assert_eq!(mimes, vec![
+            "application/json".to_string(),
+            "application/xml".to_string(),
+            "text/html".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn parse_accept_does_not_panic_on_nan_quality() {
+        // `q=NaN` parses to a float that can't be compared against itself;
+        // this must not panic, and should just fall back to the default
+        // quality of 1.0 instead of crashing the request.
+        let mimes = parse_accept("text/html;q=NaN, application/json;q=0.5");
+        assert_eq!(mimes, vec![
+            "text/html".to_string(),
+            "application/json".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn negotiate_matches_type_wildcards() {
+        let available = vec![("application/json".to_string(), json_serializer)];
+
+        let accept = parse_accept("application/*");
+        let matched = negotiate(accept.as_slice(), available.as_slice());
+        assert_eq!(matched.unwrap().0.as_slice(), "application/json");
+
+        let accept = parse_accept("text/*");
+        assert!(negotiate(accept.as_slice(), available.as_slice()).is_none());
+    }
+}